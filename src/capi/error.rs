@@ -0,0 +1,89 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{
+    capi::utils::{CHandleId, CPointerType},
+    error::ErrorCode,
+};
+
+use std::{ffi::CString, os::raw::c_char, ptr};
+
+/// A libpathrs error, as returned by pathrs_error().
+#[repr(C)]
+pub struct CError {
+    /// Human-readable description of the error.
+    pub description: *const c_char,
+    /// Stable, numeric classification of the error -- see [`ErrorCode`].
+    pub error_code: ErrorCode,
+    /// The raw `errno` that caused this error, or 0 if it was not caused by
+    /// a syscall failure.
+    pub saved_errno: i32,
+}
+
+impl Drop for CError {
+    fn drop(&mut self) {
+        if !self.description.is_null() {
+            // SAFETY: description was allocated by CString::into_raw() below.
+            let _ = unsafe { CString::from_raw(self.description as *mut c_char) };
+        }
+    }
+}
+
+/// Returns the error stored by the given libpathrs object (if any), as a
+/// human-readable description. The returned pointer must be freed with
+/// pathrs_errorinfo_free().
+///
+/// If the handle is stale or invalid, NULL is returned.
+#[no_mangle]
+pub extern "C" fn pathrs_error(ptr_type: CPointerType, id: CHandleId) -> *const CError {
+    super::utils::with_last_error(ptr_type, id, |last_error| match last_error {
+        None => ptr::null(),
+        Some(err) => {
+            let description = CString::new(err.to_string())
+                .expect("error description must not contain a NUL byte")
+                .into_raw();
+            Box::into_raw(Box::new(CError {
+                description,
+                error_code: err.error_code(),
+                saved_errno: err.errno().unwrap_or(0),
+            }))
+        }
+    })
+    .unwrap_or_else(ptr::null)
+}
+
+/// Frees the error returned by pathrs_error().
+#[no_mangle]
+pub extern "C" fn pathrs_errorinfo_free(ptr: *const CError) {
+    if !ptr.is_null() {
+        // SAFETY: ptr was allocated by Box::into_raw() above.
+        let _ = unsafe { Box::from_raw(ptr as *mut CError) };
+    }
+}
+
+/// Returns the stable [`ErrorCode`] of the error stored by the given
+/// libpathrs object (if any), without needing to allocate or free a
+/// `CError`. `ErrorCode::NoError` is returned both when there is no stored
+/// error and when the handle itself is stale or invalid.
+#[no_mangle]
+pub extern "C" fn pathrs_errorno(ptr_type: CPointerType, id: CHandleId) -> ErrorCode {
+    super::utils::with_last_error(ptr_type, id, |last_error| {
+        last_error.map_or(ErrorCode::NoError, |err| err.error_code())
+    })
+    .unwrap_or(ErrorCode::NoError)
+}
@@ -0,0 +1,27 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The C API surface of libpathrs. Everything in this module is `extern "C"`
+//! and is re-exported through `include/pathrs.h`.
+
+pub mod error;
+pub mod socket;
+pub mod transmute;
+pub mod utils;
+
+pub use utils::CPointerType;
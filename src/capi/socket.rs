@@ -0,0 +1,203 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `SCM_RIGHTS`-based transfer of libpathrs objects over `AF_UNIX` sockets.
+//!
+//! This is the socket counterpart of [`pathrs_into_fd`](super::transmute)
+//! and [`pathrs_from_fd`](super::transmute): it unwraps and wraps objects
+//! the same way and drives `sendmsg(2)`/`recvmsg(2)` itself, so callers
+//! don't have to hand-roll `cmsghdr` marshaling (a notoriously easy thing to
+//! get subtly wrong). Unlike pathrs_from_fd(), the received fd is not
+//! `fstat(2)`-checked before it is wrapped.
+
+use crate::{
+    capi::utils::{self, CHandle, CHandleId, CPointerType, CRoot, ErrorWrap},
+    error::{self, Error},
+    Handle, Root,
+};
+
+use std::{
+    fs::File,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+};
+
+use nix::{
+    cmsg_space,
+    sys::{
+        socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    },
+};
+use snafu::{OptionExt, ResultExt};
+
+/// One-byte payload sent alongside the fd, so the receiver can verify that
+/// the object it got is the type it was expecting.
+fn type_tag_byte(ptr_type: CPointerType) -> u8 {
+    ptr_type as u8
+}
+
+fn send_fd(sockfd: RawFd, ptr_type: CPointerType, fd: RawFd) -> Result<(), Error> {
+    let payload = [type_tag_byte(ptr_type)];
+    let iov = [IoVec::from_slice(&payload)];
+    let fds = [fd];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+
+    // MSG_NOSIGNAL turns a write to a peer that has closed its read end into
+    // a plain EPIPE (handled below through error::OsError) instead of
+    // raising SIGPIPE, whose default disposition would kill our caller.
+    sendmsg(sockfd, &iov, &cmsgs, MsgFlags::MSG_NOSIGNAL, None)
+        .map(|_| ())
+        .context(error::OsError)
+}
+
+/// Send a file-based libpathrs object to another process over a connected
+/// `AF_UNIX` socket, using `SCM_RIGHTS` ancillary data.
+///
+/// This consumes the original object -- its handle is no longer valid after
+/// this call -- in the same way that pathrs_into_fd() does.
+///
+/// Only PATHRS_ROOT and PATHRS_HANDLE objects can be sent with
+/// pathrs_sendmsg(). On success, 0 is returned. On error, -1 is returned. The
+/// object is consumed even when the send fails, so the error is lost (there
+/// is no object left to store it against).
+#[no_mangle]
+pub extern "C" fn pathrs_sendmsg(
+    sockfd: RawFd,
+    ptr_type: CPointerType,
+    id: CHandleId,
+) -> std::os::raw::c_int {
+    // Note: we keep the file open as a `File` (rather than converting it to
+    // a bare fd with `into_raw_fd()`) for the duration of the sendmsg(2)
+    // call, so that our local copy is always closed via `Drop` once we're
+    // done with it -- whether or not the send actually succeeded -- instead
+    // of leaking it on error.
+    let ret = match ptr_type {
+        CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => return -1,
+        CPointerType::PATHRS_ROOT => utils::take_root(id, None, |mut root| {
+            root.take_wrap_err(None, |root| {
+                let file = root.into_file();
+                Ok(Some(send_fd(sockfd, ptr_type, file.as_raw_fd())))
+            })
+        }),
+        CPointerType::PATHRS_HANDLE => utils::take_handle(id, None, |mut handle| {
+            handle.take_wrap_err(None, |handle| {
+                let file = handle.into_file();
+                Ok(Some(send_fd(sockfd, ptr_type, file.as_raw_fd())))
+            })
+        }),
+        _ => panic!("invalid ptr_type: {:?}", ptr_type),
+    };
+
+    match ret {
+        Some(Ok(())) => 0,
+        Some(Err(_)) | None => -1,
+    }
+}
+
+/// Receive a file-based libpathrs object from another process over a
+/// connected `AF_UNIX` socket, using `SCM_RIGHTS` ancillary data.
+///
+/// `expected_type` must be PATHRS_ROOT or PATHRS_HANDLE, matching what the
+/// sender passed to pathrs_sendmsg(). The received file descriptor is always
+/// given `FD_CLOEXEC` (via `MSG_CMSG_CLOEXEC`).
+///
+/// If an error occurs -- including the sender's payload byte not matching
+/// `expected_type` -- a handle of `expected_type` is returned containing the
+/// error (retrievable with pathrs_error), in the same style as
+/// pathrs_from_fd(). If `expected_type` is not one of the permitted values
+/// above, an invalid (zero) handle is returned.
+#[no_mangle]
+pub extern "C" fn pathrs_recvmsg(sockfd: RawFd, expected_type: CPointerType) -> CHandleId {
+    if !matches!(
+        expected_type,
+        CPointerType::PATHRS_ROOT | CPointerType::PATHRS_HANDLE
+    ) {
+        return CHandleId::INVALID;
+    }
+
+    let result: Result<File, Error> = (|| {
+        let mut payload = [0u8];
+        let iov = [IoVec::from_mut_slice(&mut payload)];
+        let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+
+        let msg = recvmsg(
+            sockfd,
+            &iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .context(error::OsError)?;
+
+        let fd = msg
+            .cmsgs()
+            .find_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                _ => None,
+            })
+            .context(error::InvalidArgument {
+                name: "sockfd",
+                description: "no SCM_RIGHTS fd received",
+            })?;
+
+        // Wrap the received fd in a `File` immediately, before any of the
+        // checks below, so that a failure (which may be driven by
+        // peer-controlled data) closes it via `Drop` on the error path
+        // instead of leaking it.
+        // SAFETY: We received this fd directly from recvmsg() above.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        // If the peer's ancillary data didn't fit in cmsg_buffer, the kernel
+        // truncates it -- any fds beyond the one we already pulled out above
+        // were still installed in our fd table, but silently dropped from
+        // `msg.cmsgs()`, so we have no way to find and close them. Treat
+        // this as an error rather than assume cmsg_space!([RawFd; 1]) was
+        // big enough.
+        ensure!(
+            !msg.flags().contains(MsgFlags::MSG_CTRUNC),
+            error::InvalidArgument {
+                name: "sockfd",
+                description: "control message was truncated, too many fds received",
+            }
+        );
+
+        ensure!(
+            payload == [type_tag_byte(expected_type)],
+            error::InvalidArgument {
+                name: "expected_type",
+                description: "received object type does not match expected_type",
+            }
+        );
+
+        Ok(file)
+    })();
+
+    match result {
+        Ok(file) => match expected_type {
+            CPointerType::PATHRS_ROOT => utils::alloc_root(Root::from_file_unchecked(file).into()),
+            CPointerType::PATHRS_HANDLE => {
+                utils::alloc_handle(Handle::from_file_unchecked(file).into())
+            }
+            _ => unreachable!("expected_type was already checked"),
+        },
+        Err(err) => match expected_type {
+            CPointerType::PATHRS_ROOT => utils::alloc_root(CRoot::from_err(err)),
+            CPointerType::PATHRS_HANDLE => utils::alloc_handle(CHandle::from_err(err)),
+            _ => unreachable!("expected_type was already checked"),
+        },
+    }
+}
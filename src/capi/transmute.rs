@@ -17,18 +17,18 @@
  */
 
 use crate::{
-    capi::utils::{CHandle, CPointerType, CRoot, ErrorWrap, Leakable},
+    capi::utils::{self, CHandle, CHandleId, CPointerType, CRoot, ErrorWrap},
     error::{self, Error},
-    utils::RawFdExt,
+    utils::{self as pathrs_utils, RawFdExt},
     Handle, Root,
 };
 
-use std::{
-    os::unix::io::{IntoRawFd, RawFd},
-    ptr,
-};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 
-use libc::c_void;
+/// Flag for [`pathrs_into_fd_flags`] requesting that the returned fd *not*
+/// have `FD_CLOEXEC` set (the default, used by plain [`pathrs_into_fd`], is
+/// to always set it).
+pub const PATHRS_FD_NOCLOEXEC: u32 = 1 << 0;
 
 /// Duplicate a file-based libpathrs object.
 ///
@@ -41,42 +41,26 @@ use libc::c_void;
 ///   * PATHRS_ROOT, with pathrs_root_t.
 ///   * PATHRS_HANDLE, with pathrs_handle_t.
 ///
-/// If an error occurs, NULL is returned. The object passed with this request
-/// will store the error (which can be retrieved with pathrs_error). If the
-/// object type is not one of the permitted values above, the error is lost.
+/// If an error occurs, an invalid (zero) handle is returned. If the original
+/// handle was stale, invalid, or of the wrong type, the error is lost (there
+/// is no object left to store it against).
 #[no_mangle]
-pub extern "C" fn pathrs_duplicate(ptr_type: CPointerType, ptr: *const c_void) -> *mut c_void {
-    if ptr.is_null() {
-        return ptr::null_mut();
-    }
-
-    // SAFETY: All of these casts and dereferences are safe because the C caller
-    //         has assured us that the type passed is correct. We also make sure
-    //         to not create aliased &muts by accident by destructuring the
-    //         CPointer<T>s into (inner, last_error).
+pub extern "C" fn pathrs_duplicate(ptr_type: CPointerType, id: CHandleId) -> CHandleId {
     match ptr_type {
-        CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => ptr::null_mut(),
-        CPointerType::PATHRS_ROOT => {
-            // SAFETY: See above.
-            let root = unsafe { &*(ptr as *const CRoot) };
-            root.wrap_err(ptr::null_mut(), |root| {
-                root.try_clone()
-                    .map(CRoot::from)
-                    .map(Leakable::leak)
-                    .map(|p| p as *mut _ as *mut c_void)
+        CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => CHandleId::INVALID,
+        CPointerType::PATHRS_ROOT => utils::with_root(id, CHandleId::INVALID, |root| {
+            root.wrap_err(CHandleId::INVALID, |root| {
+                root.try_clone().map(CRoot::from).map(utils::alloc_root)
             })
-        }
-        CPointerType::PATHRS_HANDLE => {
-            // SAFETY: See above.
-            let handle = unsafe { &*(ptr as *const CHandle) };
-            handle.wrap_err(ptr::null_mut(), |handle| {
+        }),
+        CPointerType::PATHRS_HANDLE => utils::with_handle(id, CHandleId::INVALID, |handle| {
+            handle.wrap_err(CHandleId::INVALID, |handle| {
                 handle
                     .try_clone()
                     .map(CHandle::from)
-                    .map(Leakable::leak)
-                    .map(|p| p as *mut _ as *mut c_void)
+                    .map(utils::alloc_handle)
             })
-        }
+        }),
         _ => panic!("invalid ptr_type: {:?}", ptr_type),
     }
 }
@@ -90,8 +74,9 @@ pub extern "C" fn pathrs_duplicate(ptr_type: CPointerType, ptr: *const c_void) -
 /// descriptor of such an object can be thought of as the "serialised" version
 /// of the object.
 ///
-/// This consumes the original object, and it is the caller's responsibility to
-/// close the file descriptor (with close) or otherwise handle its lifetime.
+/// This consumes the original object -- its handle is no longer valid after
+/// this call -- and it is the caller's responsibility to close the returned
+/// file descriptor (with close) or otherwise handle its lifetime.
 ///
 /// Only certain objects can be converted into file descriptors with
 /// pathrs_into_fd():
@@ -103,35 +88,46 @@ pub extern "C" fn pathrs_duplicate(ptr_type: CPointerType, ptr: *const c_void) -
 /// because the security properties of libpathrs depend on users doing all
 /// relevant filesystem operations through libpathrs.
 ///
-/// If an error occurs, -1 is returned. You may retrieve the error by calling
-/// pathrs_error on the passed object (as long as the object is one of the
-/// permitted ones listed above).
+/// The returned file descriptor always has `FD_CLOEXEC` set, so it is never
+/// accidentally leaked across `execve()`. If you genuinely need to pass the
+/// fd across exec (for instance to re-exec yourself with it inherited), use
+/// pathrs_into_fd_flags() with PATHRS_FD_NOCLOEXEC instead.
 ///
-/// If an error occurs, -1 is returned. The object passed with this request will
-/// store the error (which can be retrieved with pathrs_error). If the object
-/// type is not one of the permitted values above, the error is lost.
+/// If an error occurs, -1 is returned. The object is consumed even when the
+/// call fails, so the error is lost (there is no object left to store it
+/// against) and cannot be retrieved with pathrs_error().
 #[no_mangle]
-pub extern "C" fn pathrs_into_fd(ptr_type: CPointerType, ptr: *const c_void) -> RawFd {
-    if ptr.is_null() {
-        return -1;
-    }
+pub extern "C" fn pathrs_into_fd(ptr_type: CPointerType, id: CHandleId) -> RawFd {
+    pathrs_into_fd_flags(ptr_type, id, 0)
+}
 
-    // SAFETY: All of these casts and dereferences are safe because the C caller
-    //         has assured us that the type passed is correct. We also make sure
-    //         to not create aliased &muts by accident by destructuring the
-    //         CPointer<T>s into (inner, last_error).
+/// Like pathrs_into_fd(), but lets the caller control whether `FD_CLOEXEC` is
+/// set on the returned fd via `flags`.
+///
+/// `flags` is either 0 (the same behaviour as pathrs_into_fd(): `FD_CLOEXEC`
+/// is set) or PATHRS_FD_NOCLOEXEC (the returned fd will survive `execve()`).
+#[no_mangle]
+pub extern "C" fn pathrs_into_fd_flags(ptr_type: CPointerType, id: CHandleId, flags: u32) -> RawFd {
+    let cloexec = flags & PATHRS_FD_NOCLOEXEC == 0;
     match ptr_type {
         CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => -1,
-        CPointerType::PATHRS_ROOT => {
-            // SAFETY: See above.
-            let root = unsafe { &*(ptr as *const CRoot) };
-            root.take_wrap_err(-1, |root| Ok(root.into_file().into_raw_fd()))
-        }
-        CPointerType::PATHRS_HANDLE => {
-            // SAFETY: See above.
-            let handle = unsafe { &*(ptr as *const CHandle) };
-            handle.take_wrap_err(-1, |handle| Ok(handle.into_file().into_raw_fd()))
-        }
+        CPointerType::PATHRS_ROOT => utils::take_root(id, -1, |mut root| {
+            root.take_wrap_err(-1, |root| {
+                // Set FD_CLOEXEC while the fd is still owned by `file`, so
+                // that if set_cloexec() fails, `file`'s Drop closes it
+                // instead of us leaking a bare fd via into_raw_fd().
+                let file = root.into_file();
+                pathrs_utils::set_cloexec(file.as_raw_fd(), cloexec)?;
+                Ok(file.into_raw_fd())
+            })
+        }),
+        CPointerType::PATHRS_HANDLE => utils::take_handle(id, -1, |mut handle| {
+            handle.take_wrap_err(-1, |handle| {
+                let file = handle.into_file();
+                pathrs_utils::set_cloexec(file.as_raw_fd(), cloexec)?;
+                Ok(file.into_raw_fd())
+            })
+        }),
         _ => panic!("invalid ptr_type: {:?}", ptr_type),
     }
 }
@@ -159,16 +155,36 @@ pub extern "C" fn pathrs_into_fd(ptr_type: CPointerType, ptr: *const c_void) ->
 /// It is critical that the file descriptor provided has the same semantics as
 /// file descriptors which libpathrs would generate itself. This usually means
 /// that you should only ever call pathrs_from_fd() with a file descriptor that
-/// originally came from pathrs_into_fd().
+/// originally came from pathrs_into_fd(). As a sanity check, the fd is
+/// fstat(2)'d to confirm it is at least the right kind of file (a directory
+/// for PATHRS_ROOT, a regular file or directory for PATHRS_HANDLE) before
+/// being wrapped -- if you need to skip this check, use
+/// pathrs_from_fd_flags() with PATHRS_FD_NO_VALIDATE.
 ///
-/// If an error occurs, an object of the requested type is returned containing
+/// If an error occurs, a handle of the requested type is returned containing
 /// the error (which can be retrieved with pathrs_error) -- as with pathrs_open.
-/// If the object type requested is not one of the permitted values above, NULL
-/// is returned.
+/// If the object type requested is not one of the permitted values above, an
+/// invalid (zero) handle is returned.
 #[no_mangle]
-pub extern "C" fn pathrs_from_fd(fd_type: CPointerType, fd: RawFd) -> *mut c_void {
-    let mut last_error: Option<Error> = None;
-    let ret = last_error.wrap(ptr::null_mut(), move || {
+pub extern "C" fn pathrs_from_fd(fd_type: CPointerType, fd: RawFd) -> CHandleId {
+    pathrs_from_fd_flags(fd_type, fd, 0)
+}
+
+/// Flag for [`pathrs_from_fd_flags`] requesting that the incoming fd's type
+/// not be sanity-checked with `fstat(2)` -- the caller takes full
+/// responsibility for the fd being what it claims.
+pub const PATHRS_FD_NO_VALIDATE: u32 = 1 << 0;
+
+/// Like pathrs_from_fd(), but lets the caller skip the `fstat(2)`-based
+/// sanity check via `flags`.
+///
+/// `flags` is either 0 (the same behaviour as pathrs_from_fd(): the fd is
+/// validated) or PATHRS_FD_NO_VALIDATE (the fd is trusted as-is).
+#[no_mangle]
+pub extern "C" fn pathrs_from_fd_flags(fd_type: CPointerType, fd: RawFd, flags: u32) -> CHandleId {
+    let validate = flags & PATHRS_FD_NO_VALIDATE == 0;
+
+    let result: Result<CHandleId, Error> = (|| {
         ensure!(
             fd >= 0,
             error::InvalidArgument {
@@ -186,16 +202,24 @@ pub extern "C" fn pathrs_from_fd(fd_type: CPointerType, fd: RawFd) -> *mut c_voi
 
         match fd_type {
             CPointerType::PATHRS_ROOT => {
-                // SAFETY: The C caller guarantees this file is a valid Root.
+                if validate {
+                    pathrs_utils::ensure_fd_type(
+                        file.as_raw_fd(),
+                        pathrs_utils::ExpectedFdType::Directory,
+                    )?;
+                }
                 let root: CRoot = Root::from_file_unchecked(file).into();
-                // Leak and switch to void pointer.
-                Ok(root.leak() as *mut _ as *mut c_void)
+                Ok(utils::alloc_root(root))
             }
             CPointerType::PATHRS_HANDLE => {
-                // SAFETY: The C caller guarantees this file is a valid Handle.
+                if validate {
+                    pathrs_utils::ensure_fd_type(
+                        file.as_raw_fd(),
+                        pathrs_utils::ExpectedFdType::FileOrDirectory,
+                    )?;
+                }
                 let handle: CHandle = Handle::from_file_unchecked(file).into();
-                // Leak and switch to void pointer.
-                Ok(handle.leak() as *mut _ as *mut c_void)
+                Ok(utils::alloc_handle(handle))
             }
             _ => error::InvalidArgument {
                 name: "fd_type",
@@ -203,20 +227,20 @@ pub extern "C" fn pathrs_from_fd(fd_type: CPointerType, fd: RawFd) -> *mut c_voi
             }
             .fail(),
         }
-    });
+    })();
 
-    // If there was an error, we construct a new object with the requested type
+    // If there was an error, we allocate a new handle of the requested type
     // (if we can) so that the caller can get a proper error through
     // pathrs_error(). Unfortunately this is a bit ugly.
-    match last_error {
-        None => ret,
-        Some(err) => match fd_type {
-            CPointerType::PATHRS_ROOT => CRoot::from_err(err).leak() as *mut _ as *mut c_void,
-            CPointerType::PATHRS_HANDLE => CHandle::from_err(err).leak() as *mut _ as *mut c_void,
-            // Nothing more we can do. We could return a CError for
-            // PATHRS_ERROR, but callers might not correctly handle that (if you
-            // call pathrs_error(PATHRS_ERROR) you currently get NULL).
-            CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => ptr::null_mut(),
+    match result {
+        Ok(id) => id,
+        Err(err) => match fd_type {
+            CPointerType::PATHRS_ROOT => utils::alloc_root(CRoot::from_err(err)),
+            CPointerType::PATHRS_HANDLE => utils::alloc_handle(CHandle::from_err(err)),
+            // Nothing more we can do. We could allocate a PATHRS_ERROR handle,
+            // but callers might not correctly handle that (if you call
+            // pathrs_error(PATHRS_ERROR) you currently get NULL).
+            CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => CHandleId::INVALID,
             _ => panic!("invalid fd_type: {:?}", fd_type),
         },
     }
@@ -0,0 +1,548 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Support types for the C API.
+//!
+//! Rather than handing out raw pointers to heap-allocated objects (and
+//! trusting the caller to give them back to us honestly), every `pathrs_*`
+//! object is stored in a process-global slab and is only ever referenced by
+//! callers through an opaque, generation-checked [`CHandleId`]. This means a
+//! stale handle, a double-free, or a caller lying about an object's type
+//! results in a normal `Error` rather than dereferencing freed or unrelated
+//! memory. The design is modeled on the handle-map approach used by
+//! Mozilla's `ffi-support` crate.
+
+use crate::{
+    error::{self, Error},
+    Handle, Root,
+};
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Mutex, RwLock,
+};
+
+use once_cell::sync::Lazy;
+
+/// The type of object referred to by a [`CHandleId`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum CPointerType {
+    /// No object (a NULL-equivalent handle).
+    PATHRS_NONE = 0,
+    /// A `pathrs_error_t`.
+    PATHRS_ERROR = 1,
+    /// A `pathrs_root_t`.
+    PATHRS_ROOT = 2,
+    /// A `pathrs_handle_t`.
+    PATHRS_HANDLE = 3,
+}
+
+/// An opaque, generation-checked reference to a libpathrs object, handed out
+/// to C callers in place of a raw pointer.
+///
+/// A `CHandleId` packs a type tag, a per-slot generation counter, and a slot
+/// index into a single `u64`:
+///
+/// ```text
+/// | type (8) | generation (24) | index (32) |
+/// ```
+///
+/// A handle is only ever valid for the slot, generation, and type it was
+/// issued for -- once the underlying object is freed (or replaced by a new
+/// allocation reusing the same slot), the old handle's generation no longer
+/// matches and every lookup fails with a "stale handle" error instead of
+/// touching the reused slot's object.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CHandleId(pub u64);
+
+impl CHandleId {
+    /// The handle returned in place of a NULL pointer -- never issued by
+    /// [`HandleMap::alloc`].
+    pub const INVALID: CHandleId = CHandleId(0);
+}
+
+const TYPE_SHIFT: u32 = 56;
+const GENERATION_SHIFT: u32 = 32;
+const GENERATION_BITS: u32 = 24;
+const GENERATION_MASK: u32 = (1 << GENERATION_BITS) - 1;
+
+const NUM_SHARDS: u32 = 16;
+const SHARD_BITS: u32 = 4;
+const LOCAL_BITS: u32 = 32 - SHARD_BITS;
+const LOCAL_MASK: u32 = (1 << LOCAL_BITS) - 1;
+
+fn pack(type_tag: u8, generation: u32, index: u32) -> u64 {
+    ((type_tag as u64) << TYPE_SHIFT)
+        | (((generation & GENERATION_MASK) as u64) << GENERATION_SHIFT)
+        | (index as u64)
+}
+
+fn unpack(handle: u64) -> (u8, u32, u32) {
+    let type_tag = (handle >> TYPE_SHIFT) as u8;
+    let generation = ((handle >> GENERATION_SHIFT) as u32) & GENERATION_MASK;
+    let index = handle as u32;
+    (type_tag, generation, index)
+}
+
+fn stale_handle_error() -> Error {
+    error::InvalidArgument {
+        name: "handle",
+        description: "stale, invalid, or type-mismatched pathrs handle",
+    }
+    .build()
+}
+
+/// A libpathrs object kept alive in the [`HandleMap`] slab.
+pub(crate) enum CObject {
+    Root(CRoot),
+    Handle(CHandle),
+}
+
+impl CObject {
+    fn type_tag(&self) -> u8 {
+        match self {
+            CObject::Root(_) => CPointerType::PATHRS_ROOT as u8,
+            CObject::Handle(_) => CPointerType::PATHRS_HANDLE as u8,
+        }
+    }
+}
+
+/// A wrapper around a libpathrs object (`Root` or `Handle`) together with the
+/// last error it produced, so that a failed operation can still be
+/// introspected by the caller through `pathrs_error()`.
+pub(crate) struct CPointer<T> {
+    inner: Option<T>,
+    pub(crate) last_error: Option<Error>,
+}
+
+pub(crate) type CRoot = CPointer<Root>;
+pub(crate) type CHandle = CPointer<Handle>;
+
+impl<T> From<T> for CPointer<T> {
+    fn from(inner: T) -> Self {
+        Self {
+            inner: Some(inner),
+            last_error: None,
+        }
+    }
+}
+
+impl<T> CPointer<T> {
+    /// Construct a `CPointer` that holds no object, only an error.
+    pub(crate) fn from_err(err: Error) -> Self {
+        Self {
+            inner: None,
+            last_error: Some(err),
+        }
+    }
+}
+
+/// Helper trait implemented by [`CPointer<T>`] to run an operation against
+/// the wrapped object, stashing any resulting error for later retrieval via
+/// `pathrs_error()` instead of propagating it to the caller directly.
+pub(crate) trait ErrorWrap {
+    type Target;
+
+    /// Run `f` against the wrapped object (if any), returning `default` and
+    /// recording the error if `f` fails.
+    fn wrap_err<F, R>(&mut self, default: R, f: F) -> R
+    where
+        F: FnOnce(&Self::Target) -> Result<R, Error>;
+
+    /// Like [`ErrorWrap::wrap_err`], but takes ownership of the wrapped
+    /// object (used for operations like `into_file()` which consume it).
+    fn take_wrap_err<F, R>(&mut self, default: R, f: F) -> R
+    where
+        F: FnOnce(Self::Target) -> Result<R, Error>;
+}
+
+impl<T> ErrorWrap for CPointer<T> {
+    type Target = T;
+
+    fn wrap_err<F, R>(&mut self, default: R, f: F) -> R
+    where
+        F: FnOnce(&T) -> Result<R, Error>,
+    {
+        match &self.inner {
+            Some(inner) => match f(inner) {
+                Ok(ret) => ret,
+                Err(err) => {
+                    self.last_error = Some(err);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    fn take_wrap_err<F, R>(&mut self, default: R, f: F) -> R
+    where
+        F: FnOnce(T) -> Result<R, Error>,
+    {
+        match self.inner.take() {
+            Some(inner) => match f(inner) {
+                Ok(ret) => ret,
+                Err(err) => {
+                    self.last_error = Some(err);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+}
+
+struct Slot {
+    generation: u32,
+    object: Option<CObject>,
+}
+
+#[derive(Default)]
+struct Shard {
+    slots: RwLock<Vec<Slot>>,
+    free: Mutex<Vec<u32>>,
+}
+
+impl Shard {
+    fn alloc(&self, object: CObject) -> (u32, u32) {
+        if let Some(local) = self.free.lock().unwrap().pop() {
+            let mut slots = self.slots.write().unwrap();
+            let slot = &mut slots[local as usize];
+            slot.object = Some(object);
+            (local, slot.generation)
+        } else {
+            let mut slots = self.slots.write().unwrap();
+            let local = slots.len() as u32;
+            slots.push(Slot {
+                generation: 0,
+                object: Some(object),
+            });
+            (local, 0)
+        }
+    }
+
+    fn with<F, R>(&self, local: u32, generation: u32, type_tag: u8, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut CObject) -> R,
+    {
+        let mut slots = self.slots.write().unwrap();
+        let slot = slots
+            .get_mut(local as usize)
+            .ok_or_else(stale_handle_error)?;
+        if slot.generation != generation {
+            return Err(stale_handle_error());
+        }
+        match &mut slot.object {
+            Some(object) if object.type_tag() == type_tag => Ok(f(object)),
+            _ => Err(stale_handle_error()),
+        }
+    }
+
+    fn take(&self, local: u32, generation: u32, type_tag: u8) -> Result<CObject, Error> {
+        let object = {
+            let mut slots = self.slots.write().unwrap();
+            let slot = slots
+                .get_mut(local as usize)
+                .ok_or_else(stale_handle_error)?;
+            if slot.generation != generation {
+                return Err(stale_handle_error());
+            }
+            match &slot.object {
+                Some(object) if object.type_tag() == type_tag => {}
+                _ => return Err(stale_handle_error()),
+            }
+            slot.generation = slot.generation.wrapping_add(1) & GENERATION_MASK;
+            slot.object.take().expect("occupied slot checked above")
+        };
+        self.free.lock().unwrap().push(local);
+        Ok(object)
+    }
+}
+
+struct HandleMap {
+    shards: Vec<Shard>,
+    next_shard: AtomicU32,
+}
+
+impl HandleMap {
+    fn new() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| Shard::default()).collect(),
+            next_shard: AtomicU32::new(0),
+        }
+    }
+
+    fn alloc(&self, type_tag: u8, object: CObject) -> CHandleId {
+        let shard_id = self.next_shard.fetch_add(1, Ordering::Relaxed) % NUM_SHARDS;
+        let (local, generation) = self.shards[shard_id as usize].alloc(object);
+        let index = (shard_id << LOCAL_BITS) | (local & LOCAL_MASK);
+        CHandleId(pack(type_tag, generation, index))
+    }
+
+    fn with<F, R>(&self, id: CHandleId, type_tag: u8, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut CObject) -> R,
+    {
+        let (got_type, generation, index) = unpack(id.0);
+        if got_type != type_tag {
+            return Err(stale_handle_error());
+        }
+        let shard_id = index >> LOCAL_BITS;
+        let local = index & LOCAL_MASK;
+        let shard = self
+            .shards
+            .get(shard_id as usize)
+            .ok_or_else(stale_handle_error)?;
+        shard.with(local, generation, type_tag, f)
+    }
+
+    fn take(&self, id: CHandleId, type_tag: u8) -> Result<CObject, Error> {
+        let (got_type, generation, index) = unpack(id.0);
+        if got_type != type_tag {
+            return Err(stale_handle_error());
+        }
+        let shard_id = index >> LOCAL_BITS;
+        let local = index & LOCAL_MASK;
+        let shard = self
+            .shards
+            .get(shard_id as usize)
+            .ok_or_else(stale_handle_error)?;
+        shard.take(local, generation, type_tag)
+    }
+}
+
+static HANDLES: Lazy<HandleMap> = Lazy::new(HandleMap::new);
+
+/// Store a new `Root` object in the handle map, returning its handle.
+pub(crate) fn alloc_root(root: CRoot) -> CHandleId {
+    HANDLES.alloc(CPointerType::PATHRS_ROOT as u8, CObject::Root(root))
+}
+
+/// Store a new `Handle` object in the handle map, returning its handle.
+pub(crate) fn alloc_handle(handle: CHandle) -> CHandleId {
+    HANDLES.alloc(CPointerType::PATHRS_HANDLE as u8, CObject::Handle(handle))
+}
+
+/// Run `f` against the `CRoot` referred to by `id`, returning `default` if
+/// the handle is stale, invalid, or does not refer to a `CRoot`.
+pub(crate) fn with_root<F, R>(id: CHandleId, default: R, f: F) -> R
+where
+    F: FnOnce(&mut CRoot) -> R,
+{
+    HANDLES
+        .with(id, CPointerType::PATHRS_ROOT as u8, |object| match object {
+            CObject::Root(root) => f(root),
+            CObject::Handle(_) => unreachable!("type tag was already checked"),
+        })
+        .unwrap_or(default)
+}
+
+/// Run `f` against the `CHandle` referred to by `id`, returning `default` if
+/// the handle is stale, invalid, or does not refer to a `CHandle`.
+pub(crate) fn with_handle<F, R>(id: CHandleId, default: R, f: F) -> R
+where
+    F: FnOnce(&mut CHandle) -> R,
+{
+    HANDLES
+        .with(
+            id,
+            CPointerType::PATHRS_HANDLE as u8,
+            |object| match object {
+                CObject::Handle(handle) => f(handle),
+                CObject::Root(_) => unreachable!("type tag was already checked"),
+            },
+        )
+        .unwrap_or(default)
+}
+
+/// Remove the `CRoot` referred to by `id` from the handle map (freeing its
+/// slot for reuse) and run `f` against it, returning `default` if the handle
+/// was stale, invalid, or did not refer to a `CRoot`.
+pub(crate) fn take_root<F, R>(id: CHandleId, default: R, f: F) -> R
+where
+    F: FnOnce(CRoot) -> R,
+{
+    match HANDLES.take(id, CPointerType::PATHRS_ROOT as u8) {
+        Ok(CObject::Root(root)) => f(root),
+        Ok(CObject::Handle(_)) => unreachable!("type tag was already checked"),
+        Err(_) => default,
+    }
+}
+
+/// Remove the `CHandle` referred to by `id` from the handle map (freeing its
+/// slot for reuse) and run `f` against it, returning `default` if the handle
+/// was stale, invalid, or did not refer to a `CHandle`.
+pub(crate) fn take_handle<F, R>(id: CHandleId, default: R, f: F) -> R
+where
+    F: FnOnce(CHandle) -> R,
+{
+    match HANDLES.take(id, CPointerType::PATHRS_HANDLE as u8) {
+        Ok(CObject::Handle(handle)) => f(handle),
+        Ok(CObject::Root(_)) => unreachable!("type tag was already checked"),
+        Err(_) => default,
+    }
+}
+
+/// Look up the last error stored against the object of type `ptr_type`
+/// referred to by `id`, without removing it from the handle map. Returns
+/// `None` if the handle is stale, invalid, or `ptr_type` is not a
+/// `PATHRS_ROOT`/`PATHRS_HANDLE`.
+pub(crate) fn with_last_error<F, R>(ptr_type: CPointerType, id: CHandleId, f: F) -> Option<R>
+where
+    F: FnOnce(Option<&Error>) -> R,
+{
+    let type_tag = match ptr_type {
+        CPointerType::PATHRS_ROOT => CPointerType::PATHRS_ROOT as u8,
+        CPointerType::PATHRS_HANDLE => CPointerType::PATHRS_HANDLE as u8,
+        CPointerType::PATHRS_NONE | CPointerType::PATHRS_ERROR => return None,
+    };
+    HANDLES
+        .with(id, type_tag, |object| {
+            let last_error = match object {
+                CObject::Root(root) => &root.last_error,
+                CObject::Handle(handle) => &handle.last_error,
+            };
+            f(last_error.as_ref())
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    fn root_object() -> CObject {
+        let file = File::open("/").expect("opening / for a test Root");
+        CObject::Root(CRoot::from(Root::from_file_unchecked(file)))
+    }
+
+    fn handle_object() -> CObject {
+        let file = File::open("/").expect("opening / for a test Handle");
+        CObject::Handle(CHandle::from(Handle::from_file_unchecked(file)))
+    }
+
+    #[test]
+    fn take_invalidates_the_handle() {
+        let map = HandleMap::new();
+        let id = map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+
+        assert!(map.take(id, CPointerType::PATHRS_ROOT as u8).is_ok());
+        // The slot was freed by take() above, so the same handle must no
+        // longer resolve to anything -- not even the (now-vacant) slot it
+        // used to point at.
+        assert!(map
+            .with(id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_err());
+        assert!(map.take(id, CPointerType::PATHRS_ROOT as u8).is_err());
+    }
+
+    #[test]
+    fn wrong_type_tag_is_rejected() {
+        let map = HandleMap::new();
+        let id = map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+
+        // A CHandleId for a Root must not resolve when looked up as a
+        // Handle, even though the slot is still occupied.
+        assert!(map
+            .with(id, CPointerType::PATHRS_HANDLE as u8, |_| ())
+            .is_err());
+        assert!(map.take(id, CPointerType::PATHRS_HANDLE as u8).is_err());
+        // The mismatched lookups above must not have disturbed the slot.
+        assert!(map
+            .with(id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_ok());
+    }
+
+    #[test]
+    fn reused_slot_invalidates_old_generation() {
+        let map = HandleMap::new();
+        let old_id = map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+        map.take(old_id, CPointerType::PATHRS_ROOT as u8)
+            .expect("freeing the slot for reuse");
+
+        // alloc() spreads allocations round-robin across the shards, so the
+        // freed slot is only handed back out once allocation comes back
+        // around to its shard.
+        for _ in 1..NUM_SHARDS {
+            map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+        }
+        let new_id = map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+        let (_, old_generation, old_index) = unpack(old_id.0);
+        let (_, new_generation, new_index) = unpack(new_id.0);
+        assert_eq!(old_index, new_index, "expected the freed slot to be reused");
+        assert_ne!(
+            old_generation, new_generation,
+            "generation must advance when a slot is reused"
+        );
+
+        // The old handle must still be rejected, even though its slot index
+        // now refers to a live object of the same type.
+        assert!(map
+            .with(old_id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_err());
+        assert!(map
+            .with(new_id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_and_out_of_range_handles_do_not_panic() {
+        let map = HandleMap::new();
+
+        assert!(map
+            .with(CHandleId::INVALID, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_err());
+        assert!(map
+            .take(CHandleId::INVALID, CPointerType::PATHRS_ROOT as u8)
+            .is_err());
+
+        // A well-formed handle (right type tag, generation 0) pointing at a
+        // shard/slot index that was never allocated must fail gracefully
+        // rather than panicking on an out-of-bounds access.
+        let bogus = CHandleId(pack(CPointerType::PATHRS_ROOT as u8, 0, u32::MAX));
+        assert!(map
+            .with(bogus, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_err());
+        assert!(map.take(bogus, CPointerType::PATHRS_ROOT as u8).is_err());
+    }
+
+    #[test]
+    fn handle_objects_are_tracked_independently_of_roots() {
+        let map = HandleMap::new();
+        let root_id = map.alloc(CPointerType::PATHRS_ROOT as u8, root_object());
+        let handle_id = map.alloc(CPointerType::PATHRS_HANDLE as u8, handle_object());
+
+        assert!(map
+            .with(root_id, CPointerType::PATHRS_HANDLE as u8, |_| ())
+            .is_err());
+        assert!(map
+            .with(handle_id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_err());
+        assert!(map
+            .with(root_id, CPointerType::PATHRS_ROOT as u8, |_| ())
+            .is_ok());
+        assert!(map
+            .with(handle_id, CPointerType::PATHRS_HANDLE as u8, |_| ())
+            .is_ok());
+    }
+}
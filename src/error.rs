@@ -0,0 +1,113 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Error types returned by libpathrs.
+
+use std::io;
+
+use snafu::{Backtrace, Snafu};
+
+/// The various kinds of errors that libpathrs can return.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    /// The caller provided an invalid argument.
+    #[snafu(display("invalid {} argument: {}", name, description))]
+    InvalidArgument {
+        /// Name of the invalid argument.
+        name: &'static str,
+        /// Description of why the argument is invalid.
+        description: &'static str,
+        backtrace: Backtrace,
+    },
+
+    /// A syscall failed with the given errno.
+    #[snafu(display("os error: {}", source))]
+    OsError {
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+
+    /// A requested object could not be found.
+    #[snafu(display("not found: {}", description))]
+    NotFound {
+        description: &'static str,
+        backtrace: Backtrace,
+    },
+
+    /// A path resolution safety guarantee was violated (such as hitting a
+    /// symlink-loop or escaping the root).
+    #[snafu(display("safety violation: {}", description))]
+    SafetyViolation {
+        description: &'static str,
+        backtrace: Backtrace,
+    },
+
+    /// An internal invariant was violated (usually indicates a bug in
+    /// libpathrs itself, or misuse of the C API).
+    #[snafu(display("internal error: {}", description))]
+    InternalError {
+        description: &'static str,
+        backtrace: Backtrace,
+    },
+}
+
+impl Error {
+    /// The raw `errno` associated with this error, if it was caused by a
+    /// syscall failure.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::OsError { source, .. } => source.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// A stable, numeric classification of this error, for callers (notably
+    /// the C API) that want to react to specific failure modes without
+    /// parsing the human-readable message.
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            Error::InvalidArgument { .. } => ErrorCode::InvalidArgument,
+            Error::OsError { .. } => ErrorCode::OsError,
+            Error::NotFound { .. } => ErrorCode::NotFound,
+            Error::SafetyViolation { .. } => ErrorCode::SafetyViolation,
+            Error::InternalError { .. } => ErrorCode::InternalError,
+        }
+    }
+}
+
+/// A stable, machine-readable discriminant for [`Error`], analogous to
+/// `ffi-support`'s `ErrorCode`. Unlike `Error` itself, this is safe to expose
+/// across the C API as a plain integer.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No error occurred.
+    NoError = 0,
+    /// The caller provided an invalid argument.
+    InvalidArgument = 1,
+    /// A requested object could not be found.
+    NotFound = 2,
+    /// A path resolution safety guarantee was violated (symlink-loop,
+    /// root-escape, etc).
+    SafetyViolation = 3,
+    /// The underlying cause was a syscall failure; see the paired `errno`.
+    OsError = 4,
+    /// An internal invariant was violated.
+    InternalError = 5,
+}
@@ -0,0 +1,57 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A `Handle` is a reference to a file resolved through a [`Root`](crate::Root).
+
+use crate::error::{self, Error};
+
+use std::fs::File;
+
+use snafu::ResultExt;
+
+/// A handle to a file that was resolved through a `Root`, which can be used
+/// to re-open the file without re-resolving the path.
+#[derive(Debug)]
+pub struct Handle {
+    inner: File,
+}
+
+impl Handle {
+    /// Duplicate this `Handle`.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        Ok(Self {
+            inner: self.inner.try_clone().context(error::OsError)?,
+        })
+    }
+
+    /// Unwrap the `Handle` into the underlying file.
+    pub fn into_file(self) -> File {
+        self.inner
+    }
+
+    /// Construct a `Handle` from an existing `File`, without checking that
+    /// it actually refers to a valid resolved file.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the given file really was produced by
+    /// a previous [`Handle::into_file`] (or an equivalent source), since
+    /// libpathrs does not re-verify the invariants a `Handle` requires.
+    pub fn from_file_unchecked(inner: File) -> Self {
+        Self { inner }
+    }
+}
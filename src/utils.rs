@@ -0,0 +1,103 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2020 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2020 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Miscellaneous helpers shared across libpathrs.
+
+use crate::error::{self, Error};
+
+use std::{fs::File, os::unix::io::FromRawFd};
+
+use nix::fcntl::{fcntl, FcntlArg};
+use snafu::ResultExt;
+use std::os::unix::io::RawFd;
+
+/// Extension trait providing `File`-like helpers directly on a [`RawFd`].
+pub trait RawFdExt {
+    /// Duplicate the given fd into a fresh [`File`], working around the fact
+    /// that `RawFd` doesn't implement `try_clone()` directly.
+    ///
+    /// The duplicate is always created with `FD_CLOEXEC` set (via
+    /// `F_DUPFD_CLOEXEC`), so that fds libpathrs constructs from a
+    /// caller-supplied fd never leak across `execve()` by accident.
+    fn try_clone_hotfix(&self) -> Result<File, Error>;
+}
+
+impl RawFdExt for RawFd {
+    fn try_clone_hotfix(&self) -> Result<File, Error> {
+        let new_fd = fcntl(*self, FcntlArg::F_DUPFD_CLOEXEC(0)).context(error::OsError)?;
+        // SAFETY: F_DUPFD_CLOEXEC just gave us ownership of new_fd.
+        Ok(unsafe { File::from_raw_fd(new_fd) })
+    }
+}
+
+/// Set (or clear) `FD_CLOEXEC` on an existing fd, such as one still owned by
+/// a `File` that is about to be handed out with `IntoRawFd::into_raw_fd()`.
+pub(crate) fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<(), Error> {
+    let mut flags = nix::fcntl::FdFlag::from_bits_truncate(
+        fcntl(fd, FcntlArg::F_GETFD).context(error::OsError)?,
+    );
+    flags.set(nix::fcntl::FdFlag::FD_CLOEXEC, cloexec);
+    fcntl(fd, FcntlArg::F_SETFD(flags))
+        .context(error::OsError)
+        .map(|_| ())
+}
+
+/// What kind of libpathrs object a file descriptor is expected to back --
+/// used by [`ensure_fd_type`] to sanity-check a caller-supplied fd before
+/// trusting it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExpectedFdType {
+    /// Must be a directory (backs a `Root`).
+    Directory,
+    /// Must be a regular file or a directory (backs a `Handle`, which can be
+    /// resolved to either).
+    FileOrDirectory,
+}
+
+/// Verify that `fd` actually looks like the kind of object libpathrs expects
+/// to wrap it in, by `fstat(2)`-ing it, instead of blindly trusting the
+/// caller's claim (as `Root::from_file_unchecked`/`Handle::from_file_unchecked`
+/// do). This cannot catch every way a fd could violate libpathrs's
+/// invariants (a caller can still hand us a directory fd from a filesystem
+/// libpathrs doesn't support `openat2`-style resolution on), but it catches
+/// the common mistake of passing the wrong kind of fd entirely.
+pub(crate) fn ensure_fd_type(fd: RawFd, expected: ExpectedFdType) -> Result<(), Error> {
+    use nix::sys::stat::{fstat, SFlag};
+
+    let stat = fstat(fd).context(error::OsError)?;
+    // st_mode's file-type bits are a multi-bit enumeration (S_IFMT), not a
+    // set of independent flags -- S_IFSOCK and S_IFBLK both have the
+    // S_IFDIR bit set, so `SFlag::contains()` would wrongly accept them. We
+    // have to mask out S_IFMT and compare the type field for equality.
+    let file_type = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+
+    let ok = match expected {
+        ExpectedFdType::Directory => file_type == SFlag::S_IFDIR,
+        ExpectedFdType::FileOrDirectory => {
+            file_type == SFlag::S_IFDIR || file_type == SFlag::S_IFREG
+        }
+    };
+    ensure!(
+        ok,
+        error::InvalidArgument {
+            name: "fd",
+            description: "fd is not the expected file type for this pathrs object",
+        }
+    );
+    Ok(())
+}